@@ -1,3 +1,4 @@
+#![feature(allocator_api)]
 #![deny(missing_docs, warnings)]
 
 //! Traits for unsafe downcasting from trait objects to & or &mut references of
@@ -9,14 +10,24 @@
 
 extern crate traitobject;
 
-use std::any::Any;
+use std::alloc::Allocator;
+use std::any::{Any, TypeId};
 use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
 
 /// A trait providing unchecked downcasting to its contents when stored
 /// in a trait object.
 pub trait UnsafeAny: Any {}
 impl<T: Any> UnsafeAny for T {}
 
+/// Returns whether `actual` is the `TypeId` of `T`. Centralizes the
+/// type-equality check used by every checked and debug-mode downcast
+/// below, so callers never compare a `TypeId` by hand.
+fn type_id_matches<T: Any>(actual: TypeId) -> bool {
+    actual == TypeId::of::<T>()
+}
+
 impl UnsafeAny {
     /// Returns a reference to the contained value, assuming that it is of type `T`.
     ///
@@ -24,6 +35,7 @@ impl UnsafeAny {
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
     pub unsafe fn downcast_ref_unchecked<T: Any>(&self) -> &T {
+        debug_assert!(type_id_matches::<T>(Any::type_id(self)));
         mem::transmute(traitobject::data(self))
     }
 
@@ -33,28 +45,67 @@ impl UnsafeAny {
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
     pub unsafe fn downcast_mut_unchecked<T: Any>(&mut self) -> &mut T {
+        debug_assert!(type_id_matches::<T>(Any::type_id(self)));
         mem::transmute(traitobject::data_mut(self))
     }
 
-    /// Returns a the contained value, assuming that it is of type `T`.
+    /// Returns a the contained value, assuming that it is of type `T`, for a
+    /// box using the allocator `A`.
     ///
     /// ## Warning
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
-    pub unsafe fn downcast_unchecked<T: Any>(self: Box<UnsafeAny>) -> Box<T> {
-        let raw: *mut UnsafeAny = mem::transmute(self);
-        mem::transmute(traitobject::data_mut(raw))
+    pub unsafe fn downcast_unchecked<T: Any, A: Allocator>(self: Box<UnsafeAny, A>) -> Box<T, A> {
+        debug_assert!(type_id_matches::<T>(Any::type_id(&*self)));
+        let (raw, alloc) = Box::into_raw_with_allocator(self);
+        let data = traitobject::data_mut(raw) as *mut T;
+        Box::from_raw_in(data, alloc)
+    }
+
+    /// Returns a reference to the contained value if it is of type `T`, or
+    /// `None` if it is not.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        if type_id_matches::<T>(Any::type_id(self)) {
+            Some(unsafe { self.downcast_ref_unchecked() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the contained value if it is of type
+    /// `T`, or `None` if it is not.
+    pub fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        if type_id_matches::<T>(Any::type_id(self)) {
+            Some(unsafe { self.downcast_mut_unchecked() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the contained value if it is of type `T`, or `Err(self)` if
+    /// it is not.
+    pub fn downcast<T: Any>(self: Box<UnsafeAny>) -> Result<Box<T>, Box<UnsafeAny>> {
+        if type_id_matches::<T>(Any::type_id(&*self)) {
+            Ok(unsafe { self.downcast_unchecked() })
+        } else {
+            Err(self)
+        }
     }
 }
 
 /// An extension trait for unchecked downcasting of trait objects.
 pub unsafe trait UnsafeAnyExt {
+    /// Returns the `TypeId` of the underlying concrete type, so that the
+    /// safe downcast methods can check it before transmuting.
+    fn type_id(&self) -> TypeId;
+
     /// Returns a reference to the contained value, assuming that it is of type `T`.
     ///
     /// ## Warning
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
     unsafe fn downcast_ref_unchecked<T: Any>(&self) -> &T {
+        debug_assert!(type_id_matches::<T>(self.type_id()));
         mem::transmute(traitobject::data(self))
     }
 
@@ -64,6 +115,7 @@ pub unsafe trait UnsafeAnyExt {
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
     unsafe fn downcast_mut_unchecked<T: Any>(&mut self) -> &mut T {
+        debug_assert!(type_id_matches::<T>((*self).type_id()));
         mem::transmute(traitobject::data_mut(self))
     }
 
@@ -73,23 +125,192 @@ pub unsafe trait UnsafeAnyExt {
     ///
     /// If you are not _absolutely certain_ of `T` you should _not_ call this!
     unsafe fn downcast_unchecked<T: Any>(self: Box<Self>) -> Box<T> {
+        debug_assert!(type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)));
         let raw: *mut Self = mem::transmute(self);
         mem::transmute(traitobject::data_mut(raw))
     }
+
+    /// Returns a reference to the contained value if it is of type `T`, or
+    /// `None` if it is not.
+    fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        if type_id_matches::<T>(self.type_id()) {
+            Some(unsafe { self.downcast_ref_unchecked() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the contained value if it is of type
+    /// `T`, or `None` if it is not.
+    fn downcast_mut<T: Any>(&mut self) -> Option<&mut T> {
+        if type_id_matches::<T>((*self).type_id()) {
+            Some(unsafe { self.downcast_mut_unchecked() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the contained value if it is of type `T`, or `Err(self)` if
+    /// it is not.
+    fn downcast<T: Any>(self: Box<Self>) -> Result<Box<T>, Box<Self>> {
+        if type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)) {
+            Ok(unsafe { self.downcast_unchecked() })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Returns the contained value, assuming that it is of type `T`.
+    ///
+    /// ## Warning
+    ///
+    /// If you are not _absolutely certain_ of `T` you should _not_ call this!
+    unsafe fn downcast_rc_unchecked<T: Any>(self: Rc<Self>) -> Rc<T> {
+        debug_assert!(type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)));
+        let raw: *const Self = Rc::into_raw(self);
+        let data = traitobject::data(&*raw) as *const T;
+        Rc::from_raw(data)
+    }
+
+    /// Returns the contained value, assuming that it is of type `T`.
+    ///
+    /// ## Warning
+    ///
+    /// If you are not _absolutely certain_ of `T` you should _not_ call this!
+    unsafe fn downcast_arc_unchecked<T: Any>(self: Arc<Self>) -> Arc<T> {
+        debug_assert!(type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)));
+        let raw: *const Self = Arc::into_raw(self);
+        let data = traitobject::data(&*raw) as *const T;
+        Arc::from_raw(data)
+    }
+
+    /// Returns the contained value if it is of type `T`, or `Err(self)` if
+    /// it is not.
+    fn downcast_rc<T: Any>(self: Rc<Self>) -> Result<Rc<T>, Rc<Self>> {
+        if type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)) {
+            Ok(unsafe { self.downcast_rc_unchecked() })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Returns the contained value if it is of type `T`, or `Err(self)` if
+    /// it is not.
+    fn downcast_arc<T: Any>(self: Arc<Self>) -> Result<Arc<T>, Arc<Self>> {
+        if type_id_matches::<T>(UnsafeAnyExt::type_id(&*self)) {
+            Ok(unsafe { self.downcast_arc_unchecked() })
+        } else {
+            Err(self)
+        }
+    }
 }
 
-unsafe impl UnsafeAnyExt for Any { }
-unsafe impl UnsafeAnyExt for UnsafeAny { }
-unsafe impl UnsafeAnyExt for Any + Send { }
-unsafe impl UnsafeAnyExt for Any + Sync { }
-unsafe impl UnsafeAnyExt for Any + Send + Sync { }
-unsafe impl UnsafeAnyExt for UnsafeAny + Send { }
-unsafe impl UnsafeAnyExt for UnsafeAny + Sync { }
-unsafe impl UnsafeAnyExt for UnsafeAny + Send + Sync { }
+unsafe impl UnsafeAnyExt for Any {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for UnsafeAny {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for Any + Send {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for Any + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for Any + Send + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for UnsafeAny + Send {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for UnsafeAny + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for UnsafeAny + Send + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+
+/// A version of the `Any` trait that also requires `Clone`, so that trait
+/// objects built on top of it can still be cloned even though `Clone` itself
+/// is not object-safe.
+///
+/// A blanket impl is provided for all `Any + Clone` types, backed by a
+/// hidden `clone_box` method that `Box<CloneAny>` uses to implement `Clone`.
+pub trait CloneAny: Any {
+    #[doc(hidden)]
+    fn clone_box(&self) -> Box<CloneAny>;
+}
+
+impl<T: Any + Clone> CloneAny for T {
+    fn clone_box(&self) -> Box<CloneAny> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<CloneAny> {
+    fn clone(&self) -> Box<CloneAny> {
+        (**self).clone_box()
+    }
+}
+
+unsafe impl UnsafeAnyExt for CloneAny {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for CloneAny + Send {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for CloneAny + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+unsafe impl UnsafeAnyExt for CloneAny + Send + Sync {
+    fn type_id(&self) -> TypeId { Any::type_id(self) }
+}
+
+/// Implements `UnsafeAnyExt` for a user-defined trait that is itself
+/// bounded by `Any`, covering the bare trait object and all four
+/// `Send`/`Sync` combinations.
+///
+/// This is the same boilerplate used internally for `UnsafeAny` and
+/// `CloneAny`, exposed so that other crates defining their own
+/// `Any`-bounded trait (for example a `Component: Any` trait) can opt in to
+/// unchecked downcasting without repeating it by hand.
+///
+/// ```ignore
+/// trait Component: Any {}
+/// unsafe_any_impl!(Component);
+/// ```
+#[macro_export]
+macro_rules! unsafe_any_impl {
+    ($t:ident) => {
+        unsafe impl $crate::UnsafeAnyExt for $t {
+            fn type_id(&self) -> ::std::any::TypeId {
+                ::std::any::Any::type_id(self)
+            }
+        }
+
+        unsafe impl $crate::UnsafeAnyExt for $t + Send {
+            fn type_id(&self) -> ::std::any::TypeId {
+                ::std::any::Any::type_id(self)
+            }
+        }
+
+        unsafe impl $crate::UnsafeAnyExt for $t + Sync {
+            fn type_id(&self) -> ::std::any::TypeId {
+                ::std::any::Any::type_id(self)
+            }
+        }
+
+        unsafe impl $crate::UnsafeAnyExt for $t + Send + Sync {
+            fn type_id(&self) -> ::std::any::TypeId {
+                ::std::any::Any::type_id(self)
+            }
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::{UnsafeAny, UnsafeAnyExt};
+    use super::{UnsafeAny, UnsafeAnyExt, CloneAny};
     use std::any::Any;
 
     #[test] fn test_simple_downcast_ext() {
@@ -120,6 +341,57 @@ mod test {
         }
     }
 
+    #[test] fn test_checked_downcast_ext() {
+        // Called through the trait, since `dyn Any` and `Box<dyn Any>` have
+        // inherent methods of the same names in std.
+        let a = Box::new(7usize) as Box<Any>;
+        assert_eq!(*UnsafeAnyExt::downcast_ref::<usize>(&*a).unwrap(), 7);
+        assert!(UnsafeAnyExt::downcast_ref::<isize>(&*a).is_none());
+
+        let mut a = Box::new(7usize) as Box<Any>;
+        *UnsafeAnyExt::downcast_mut::<usize>(&mut *a).unwrap() = 8;
+        assert_eq!(*UnsafeAnyExt::downcast_ref::<usize>(&*a).unwrap(), 8);
+        assert!(UnsafeAnyExt::downcast_mut::<isize>(&mut *a).is_none());
+
+        let a = Box::new(7usize) as Box<Any>;
+        let a = UnsafeAnyExt::downcast::<isize>(a).unwrap_err();
+        assert_eq!(*UnsafeAnyExt::downcast::<usize>(a).unwrap(), 7);
+    }
+
+    #[test] fn test_checked_downcast_inherent() {
+        let a = Box::new(7usize) as Box<UnsafeAny>;
+        assert_eq!(*a.downcast_ref::<usize>().unwrap(), 7);
+        assert!(a.downcast_ref::<isize>().is_none());
+
+        let mut a = Box::new(7usize) as Box<UnsafeAny>;
+        *a.downcast_mut::<usize>().unwrap() = 8;
+        assert_eq!(*a.downcast_ref::<usize>().unwrap(), 8);
+        assert!(a.downcast_mut::<isize>().is_none());
+
+        let a = Box::new(7usize) as Box<UnsafeAny>;
+        let a = a.downcast::<isize>().unwrap_err();
+        match a.downcast::<usize>() {
+            Ok(a) => assert_eq!(*a, 7),
+            Err(_) => panic!("downcast to the correct type failed"),
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_mismatched_unchecked_downcast_panics_in_debug() {
+        let a = Box::new(7usize) as Box<UnsafeAny>;
+        unsafe { a.downcast_ref_unchecked::<isize>(); }
+    }
+
+    #[test] fn test_box_downcast_unchecked_with_allocator() {
+        use std::alloc::Global;
+
+        let a = Box::new_in(7usize, Global) as Box<UnsafeAny, Global>;
+        let a = unsafe { a.downcast_unchecked::<usize, Global>() };
+        assert_eq!(*a, 7);
+    }
+
     #[test] fn test_box_downcast_no_double_free() {
         use std::sync::atomic::{AtomicUsize, Ordering};
         use std::sync::Arc;
@@ -137,7 +409,7 @@ mod test {
         let x = Arc::new(AtomicUsize::new(0));
         let a = Box::new(Dropper { x: x.clone() }) as Box<UnsafeAny>;
 
-        let dropper = unsafe { a.downcast_unchecked::<Dropper>() };
+        let dropper = unsafe { a.downcast_unchecked::<Dropper, _>() };
         drop(dropper);
 
         assert_eq!(x.load(Ordering::SeqCst), 1);
@@ -151,5 +423,86 @@ mod test {
 
         assert_eq!(x.load(Ordering::SeqCst), 1);
     }
-}
 
+    #[test] fn test_arc_downcast_no_double_free() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct Dropper {
+            x: Arc<AtomicUsize>
+        }
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                self.x.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let x = Arc::new(AtomicUsize::new(0));
+        let a = Arc::new(Dropper { x: x.clone() }) as Arc<UnsafeAny + Send + Sync>;
+        let b = a.clone();
+
+        let dropper = unsafe { a.downcast_arc_unchecked::<Dropper>() };
+        drop(dropper);
+        assert_eq!(x.load(Ordering::SeqCst), 0);
+
+        drop(b);
+        assert_eq!(x.load(Ordering::SeqCst), 1);
+
+        // Test the safe, checked variant.
+        let x = Arc::new(AtomicUsize::new(0));
+        let a = Arc::new(Dropper { x: x.clone() }) as Arc<Any + Send + Sync>;
+
+        assert!(a.clone().downcast_arc::<usize>().is_err());
+
+        let dropper = a.downcast_arc::<Dropper>().unwrap();
+        drop(dropper);
+
+        assert_eq!(x.load(Ordering::SeqCst), 1);
+    }
+
+    #[test] fn test_rc_downcast_no_double_free() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct Dropper {
+            x: Rc<Cell<usize>>
+        }
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                self.x.set(self.x.get() + 1);
+            }
+        }
+
+        let x = Rc::new(Cell::new(0));
+        let a = Rc::new(Dropper { x: x.clone() }) as Rc<UnsafeAny>;
+        let b = a.clone();
+
+        let dropper = unsafe { a.downcast_rc_unchecked::<Dropper>() };
+        drop(dropper);
+        assert_eq!(x.get(), 0);
+
+        drop(b);
+        assert_eq!(x.get(), 1);
+    }
+
+    #[test] fn test_clone_any() {
+        let a = Box::new(7usize) as Box<CloneAny>;
+        let b = a.clone();
+
+        unsafe {
+            assert_eq!(*a.downcast_ref_unchecked::<usize>(), 7);
+            assert_eq!(*b.downcast_ref_unchecked::<usize>(), 7);
+        }
+    }
+
+    #[test] fn test_unsafe_any_impl_macro() {
+        trait Component: Any {}
+        impl<T: Any> Component for T {}
+        unsafe_any_impl!(Component);
+
+        let a = Box::new(7usize) as Box<Component>;
+        unsafe { assert_eq!(*a.downcast_ref_unchecked::<usize>(), 7); }
+    }
+}